@@ -0,0 +1,48 @@
+// Content-defined chunking used to split file data into dedupe-friendly
+// blocks, following the rolling-hash cutpoint scheme used by backup tools
+// such as zvault: a boundary falls wherever the hash of the trailing
+// WINDOW_SIZE bytes is zero under AVG_CHUNK_SIZE's mask, clamped to
+// [MIN_CHUNK_SIZE, MAX_CHUNK_SIZE] so no chunk is pathologically small or
+// large.
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+pub const AVG_CHUNK_SIZE: usize = 8 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+const WINDOW_SIZE: usize = 48;
+const PRIME: u64 = 1_099_511_628_211;
+
+fn window_pow() -> u64 {
+    let mut result: u64 = 1;
+    for _ in 0..WINDOW_SIZE {
+        result = result.wrapping_mul(PRIME);
+    }
+    result
+}
+
+/// Splits `data` into content-defined chunks, returning borrowed slices in order.
+pub fn chunk(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        return vec![];
+    }
+    let mask = (AVG_CHUNK_SIZE - 1) as u64;
+    let pow = window_pow();
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0usize;
+    let mut hash: u64 = 0;
+    for i in 0..data.len() {
+        hash = hash.wrapping_mul(PRIME).wrapping_add(data[i] as u64);
+        if i >= WINDOW_SIZE {
+            let oldest = data[i - WINDOW_SIZE];
+            hash = hash.wrapping_sub((oldest as u64).wrapping_mul(pow));
+        }
+        let chunk_len = i - chunk_start + 1;
+        let at_content_boundary = chunk_len >= MIN_CHUNK_SIZE && hash & mask == 0;
+        let at_max_size = chunk_len >= MAX_CHUNK_SIZE;
+        let at_end = i == data.len() - 1;
+        if at_content_boundary || at_max_size || at_end {
+            chunks.push(&data[chunk_start..=i]);
+            chunk_start = i + 1;
+        }
+    }
+    chunks
+}
@@ -1,20 +1,67 @@
+extern crate bincode;
+extern crate blake3;
 extern crate env_logger;
 extern crate fuse;
 extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate time;
+extern crate zstd;
+
+mod chunking;
+mod persistence;
 
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyWrite, Request,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::{EACCES, EEXIST, ENOENT};
+use libc::{EACCES, EEXIST, ENODATA, ENOENT, ERANGE};
+use persistence::Snapshot;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
-use std::{collections::HashMap, str::FromStr};
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use time::Timespec;
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
+const BLOCK_SIZE: u64 = 512;
+const DEFAULT_CAPACITY_BYTES: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+const BYTES_PER_INODE: u64 = 16 * 1024; // same ratio ext4 defaults to with -T default
+
+fn blocks_for_size(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE)
+}
+
+const S_ISUID: u16 = 0o4000;
+const S_ISGID: u16 = 0o2000;
+
+// Ports the owner/group/other permission check ayafs' myfs applies before
+// serving a request: root bypasses the check, otherwise the requested
+// access mask (R_OK/W_OK/X_OK) is matched against whichever triad
+// (owner/group/other) the requesting uid/gid falls into.
+fn check_access(file_uid: u32, file_gid: u32, file_perm: u16, uid: u32, gid: u32, mut mask: i32) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    mask &= libc::R_OK | libc::W_OK | libc::X_OK;
+    if mask == 0 {
+        return true;
+    }
+
+    let triad = if uid == file_uid {
+        (file_perm >> 6) & 0o7
+    } else if gid == file_gid {
+        (file_perm >> 3) & 0o7
+    } else {
+        file_perm & 0o7
+    };
+    mask & !(triad as i32) == 0
+}
+
 type INode = u64;
 
 struct HardLink {
@@ -22,15 +69,260 @@ struct HardLink {
     name: String,
 }
 
+type ChunkHash = [u8; 32];
+
+#[derive(Clone)]
+struct ChunkRef {
+    hash: ChunkHash,
+    len: usize,
+}
+
 struct File {
     hard_links: Vec<HardLink>,
     attr: FileAttr,
     generation: u64,
+    chunks: Vec<ChunkRef>,
+    xattrs: HashMap<String, Vec<u8>>,
 }
 
 struct MemFS {
-    inodes: HashMap<INode, File>,  // <ino, File>
-    datas: HashMap<INode, String>, // <ino, file_data>
+    inodes: HashMap<INode, File>,          // <ino, File>
+    chunks: HashMap<ChunkHash, Vec<u8>>,   // <chunk_hash, chunk_data>
+    chunk_refs: HashMap<ChunkHash, usize>, // <chunk_hash, reference_count>
+    snapshot_path: Option<PathBuf>,
+    capacity_bytes: u64,
+    next_inode: AtomicU64,
+}
+
+impl MemFS {
+    #[cfg(test)]
+    fn new(snapshot_path: Option<PathBuf>) -> MemFS {
+        MemFS::with_capacity(snapshot_path, DEFAULT_CAPACITY_BYTES)
+    }
+
+    fn with_capacity(snapshot_path: Option<PathBuf>, capacity_bytes: u64) -> MemFS {
+        let mut inodes = HashMap::new();
+        // i-node numberの1はroot node, 0はbad block
+        inodes.insert(
+            1,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 0,
+                    name: "/".to_string(),
+                }],
+                attr: new_file_attr(1, 0, FileType::Directory, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+        MemFS {
+            inodes: inodes,
+            chunks: HashMap::new(),
+            chunk_refs: HashMap::new(),
+            snapshot_path: snapshot_path,
+            capacity_bytes: capacity_bytes,
+            next_inode: AtomicU64::new(2),
+        }
+    }
+
+    // Hands out a fresh inode number on every create/mkdir/symlink, avoiding
+    // the same-second collisions a time-based inode would produce under a
+    // tight creation loop.
+    fn alloc_inode(&self) -> INode {
+        self.next_inode.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Keeps the allocator ahead of any inode loaded from a snapshot so newly
+    // created files can never collide with restored ones.
+    fn bump_next_inode_past(&self, ino: INode) {
+        self.next_inode.fetch_max(ino + 1, Ordering::SeqCst);
+    }
+
+    fn load_snapshot(&mut self) {
+        let path = match &self.snapshot_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if let Ok(snapshot) = Snapshot::load(&path) {
+            let (inodes, chunks, chunk_refs) = snapshot.restore();
+            if let Some(&max_ino) = inodes.keys().max() {
+                self.bump_next_inode_past(max_ino);
+            }
+            self.inodes = inodes;
+            self.chunks = chunks;
+            self.chunk_refs = chunk_refs;
+        }
+    }
+
+    fn save_snapshot(&self) {
+        if let Some(path) = &self.snapshot_path {
+            Snapshot::capture(&self.inodes, &self.chunks, &self.chunk_refs)
+                .save(path)
+                .expect("fail to save snapshot");
+        }
+    }
+
+    // Total inode capacity, derived from capacity_bytes the same way
+    // real filesystems size their inode table: one inode per
+    // BYTES_PER_INODE of backing capacity.
+    fn total_inodes(&self) -> u64 {
+        self.capacity_bytes / BYTES_PER_INODE
+    }
+
+    fn file_content(&self, ino: INode) -> Vec<u8> {
+        let f = match self.inodes.get(&ino) {
+            Some(f) => f,
+            None => return Vec::new(),
+        };
+        let mut content = Vec::with_capacity(f.chunks.iter().map(|c| c.len).sum());
+        for chunk_ref in &f.chunks {
+            if let Some(data) = self.chunks.get(&chunk_ref.hash) {
+                content.extend_from_slice(data);
+            }
+        }
+        content
+    }
+
+    fn release_chunk(&mut self, hash: ChunkHash) {
+        if let Some(count) = self.chunk_refs.get_mut(&hash) {
+            *count -= 1;
+            if *count == 0 {
+                self.chunk_refs.remove(&hash);
+                self.chunks.remove(&hash);
+            }
+        }
+    }
+
+    // Re-chunks `content` and points `ino`'s chunk list at the result,
+    // releasing its previous chunks and deduplicating against the store.
+    fn store_content(&mut self, ino: INode, content: &[u8]) {
+        let old_chunks = self
+            .inodes
+            .get(&ino)
+            .map(|f| f.chunks.clone())
+            .unwrap_or_default();
+        for chunk_ref in old_chunks {
+            self.release_chunk(chunk_ref.hash);
+        }
+
+        let mut new_chunks = Vec::new();
+        for piece in chunking::chunk(content) {
+            let hash: ChunkHash = *blake3::hash(piece).as_bytes();
+            self.chunks.entry(hash).or_insert_with(|| piece.to_vec());
+            *self.chunk_refs.entry(hash).or_insert(0) += 1;
+            new_chunks.push(ChunkRef {
+                hash: hash,
+                len: piece.len(),
+            });
+        }
+        if let Some(f) = self.inodes.get_mut(&ino) {
+            f.chunks = new_chunks;
+        }
+    }
+
+    // Splits `ino`'s chunk list at the chunk boundary at-or-before `cut`:
+    // chunks entirely before it are returned untouched as `prefix`, while
+    // the rest are reconstructed into `tail_content`, released from the
+    // store, and handed back for the caller to edit before re-chunking.
+    // Returns (prefix, tail_content, tail_start).
+    fn split_for_edit(&mut self, ino: INode, cut: usize) -> (Vec<ChunkRef>, Vec<u8>, usize) {
+        let (prefix, tail, tail_start) = {
+            let f = match self.inodes.get(&ino) {
+                Some(f) => f,
+                None => return (Vec::new(), Vec::new(), 0),
+            };
+            let mut prefix = Vec::new();
+            let mut tail = Vec::new();
+            let mut pos = 0usize;
+            let mut tail_start = 0usize;
+            let mut in_tail = false;
+            for chunk_ref in &f.chunks {
+                if !in_tail && pos + chunk_ref.len > cut {
+                    in_tail = true;
+                    tail_start = pos;
+                }
+                if in_tail {
+                    tail.push(chunk_ref.clone());
+                } else {
+                    prefix.push(chunk_ref.clone());
+                }
+                pos += chunk_ref.len;
+            }
+            if !in_tail {
+                tail_start = pos;
+            }
+            (prefix, tail, tail_start)
+        };
+
+        let mut tail_content = Vec::with_capacity(tail.iter().map(|c| c.len).sum());
+        for chunk_ref in &tail {
+            if let Some(bytes) = self.chunks.get(&chunk_ref.hash) {
+                tail_content.extend_from_slice(bytes);
+            }
+        }
+        for chunk_ref in tail {
+            self.release_chunk(chunk_ref.hash);
+        }
+        (prefix, tail_content, tail_start)
+    }
+
+    // Re-chunks `tail_content`, appends the result to `prefix`, points
+    // `ino`'s chunk list at the splice, and returns the file's new total
+    // size.
+    fn splice_tail(&mut self, ino: INode, prefix: Vec<ChunkRef>, tail_content: &[u8]) -> u64 {
+        let mut new_chunks = prefix;
+        for piece in chunking::chunk(tail_content) {
+            let hash: ChunkHash = *blake3::hash(piece).as_bytes();
+            self.chunks.entry(hash).or_insert_with(|| piece.to_vec());
+            *self.chunk_refs.entry(hash).or_insert(0) += 1;
+            new_chunks.push(ChunkRef {
+                hash: hash,
+                len: piece.len(),
+            });
+        }
+
+        let new_len = new_chunks.iter().map(|c| c.len as u64).sum();
+        if let Some(f) = self.inodes.get_mut(&ino) {
+            f.chunks = new_chunks;
+        }
+        new_len
+    }
+
+    // Applies `data` at `offset` by rebuilding only the chunk list's tail:
+    // chunks entirely before the boundary preceding `offset` are kept
+    // as-is, and the tail (old tail content with `data` overlaid,
+    // growing the file if the write extends past its current end) is
+    // the only part re-chunked and deduplicated. Returns the file's new
+    // total size.
+    fn write_range(&mut self, ino: INode, offset: usize, data: &[u8]) -> u64 {
+        if !self.inodes.contains_key(&ino) {
+            return 0;
+        }
+        let (prefix, mut tail_content, tail_start) = self.split_for_edit(ino, offset);
+
+        let rel_offset = offset - tail_start;
+        let end = rel_offset + data.len();
+        if tail_content.len() < end {
+            tail_content.resize(end, 0);
+        }
+        tail_content[rel_offset..end].copy_from_slice(data);
+
+        self.splice_tail(ino, prefix, &tail_content)
+    }
+
+    // Truncates or zero-extends `ino`'s content to exactly `size` bytes,
+    // keeping chunks entirely before the cut point untouched and only
+    // re-chunking the truncated/extended tail. Returns the file's new
+    // total size (== `size`, unless `ino` doesn't exist).
+    fn truncate_content(&mut self, ino: INode, size: usize) -> u64 {
+        if !self.inodes.contains_key(&ino) {
+            return 0;
+        }
+        let (prefix, mut tail_content, tail_start) = self.split_for_edit(ino, size);
+        tail_content.resize(size.saturating_sub(tail_start), 0);
+        self.splice_tail(ino, prefix, &tail_content)
+    }
 }
 
 fn new_file_attr(ino: INode, size: u64, ftype: FileType, uid: u32, gid: u32) -> FileAttr {
@@ -38,7 +330,7 @@ fn new_file_attr(ino: INode, size: u64, ftype: FileType, uid: u32, gid: u32) ->
     FileAttr {
         ino: ino,
         size: size,
-        blocks: 0,
+        blocks: blocks_for_size(size),
         atime: t,
         mtime: t,
         ctime: t,
@@ -60,6 +352,15 @@ fn new_file_attr(ino: INode, size: u64, ftype: FileType, uid: u32, gid: u32) ->
 }
 
 impl Filesystem for MemFS {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        self.load_snapshot();
+        Ok(())
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        self.save_snapshot();
+    }
+
     fn getattr(&mut self, _req: &Request, ino: INode, reply: ReplyAttr) {
         for (&inode, f) in self.inodes.iter() {
             if ino == inode {
@@ -78,6 +379,20 @@ impl Filesystem for MemFS {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        if let Some(f) = self.inodes.get(&ino) {
+            if !check_access(
+                f.attr.uid,
+                f.attr.gid,
+                f.attr.perm,
+                _req.uid(),
+                _req.gid(),
+                libc::R_OK,
+            ) {
+                reply.error(EACCES);
+                return;
+            }
+        }
+
         if offset > 0 {
             reply.ok();
             return;
@@ -98,6 +413,20 @@ impl Filesystem for MemFS {
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if let Some(f) = self.inodes.get(&parent) {
+            if !check_access(
+                f.attr.uid,
+                f.attr.gid,
+                f.attr.perm,
+                _req.uid(),
+                _req.gid(),
+                libc::X_OK,
+            ) {
+                reply.error(EACCES);
+                return;
+            }
+        }
+
         let n = name.to_str().unwrap();
         for (_, f) in self.inodes.iter() {
             if let Some(_) = f
@@ -121,7 +450,7 @@ impl Filesystem for MemFS {
         _flag: u32,
         reply: ReplyCreate,
     ) {
-        let inode = time::now().to_timespec().sec as u64;
+        let inode = self.alloc_inode();
         let f = new_file_attr(inode, 0, FileType::RegularFile, _req.uid(), _req.gid());
         self.inodes.insert(
             inode,
@@ -132,6 +461,8 @@ impl Filesystem for MemFS {
                 }],
                 attr: f,
                 generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
             },
         );
         reply.created(&TTL, &f, 0, 0, 0);
@@ -145,7 +476,7 @@ impl Filesystem for MemFS {
         _mode: u32,
         reply: ReplyEntry,
     ) {
-        let inode = time::now().to_timespec().sec as u64;
+        let inode = self.alloc_inode();
         let f = new_file_attr(inode, 0, FileType::Directory, _req.uid(), _req.gid());
         self.inodes.insert(
             inode,
@@ -156,6 +487,8 @@ impl Filesystem for MemFS {
                 }],
                 attr: f,
                 generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
             },
         );
         reply.entry(&TTL, &f, 0);
@@ -178,9 +511,17 @@ impl Filesystem for MemFS {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        if !self.inodes.contains_key(&ino) {
+            reply.error(EACCES);
+            return;
+        }
+        if let Some(size) = _size {
+            self.truncate_content(ino, size as usize);
+        }
         match self.inodes.get_mut(&ino) {
             Some(mut f) => {
                 f.attr.size = _size.unwrap_or(f.attr.size);
+                f.attr.blocks = blocks_for_size(f.attr.size);
                 f.attr.uid = _uid.unwrap_or(f.attr.uid);
                 f.attr.gid = _gid.unwrap_or(f.attr.gid);
                 f.attr.mtime = _mtime.unwrap_or(f.attr.mtime);
@@ -192,6 +533,26 @@ impl Filesystem for MemFS {
         }
     }
 
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let used_bytes: u64 = self.chunks.values().map(|data| data.len() as u64).sum();
+        let total_blocks = self.capacity_bytes / BLOCK_SIZE;
+        let used_blocks = blocks_for_size(used_bytes);
+        let free_blocks = total_blocks.saturating_sub(used_blocks);
+        let total_inodes = self.total_inodes();
+        let used_inodes = self.inodes.len() as u64;
+        let free_inodes = total_inodes.saturating_sub(used_inodes);
+        reply.statfs(
+            total_blocks,
+            free_blocks,
+            free_blocks,
+            total_inodes,
+            free_inodes,
+            BLOCK_SIZE as u32,
+            255,
+            BLOCK_SIZE as u32,
+        );
+    }
+
     fn write(
         &mut self,
         _req: &Request,
@@ -202,11 +563,35 @@ impl Filesystem for MemFS {
         _flags: u32,
         reply: ReplyWrite,
     ) {
+        match self.inodes.get(&ino) {
+            Some(f) => {
+                if !check_access(
+                    f.attr.uid,
+                    f.attr.gid,
+                    f.attr.perm,
+                    _req.uid(),
+                    _req.gid(),
+                    libc::W_OK,
+                ) {
+                    reply.error(EACCES);
+                    return;
+                }
+            }
+            None => {
+                reply.error(EACCES);
+                return;
+            }
+        }
+
+        let offset = _offset as usize;
         let length: usize = data.len();
-        let x = String::from_utf8(data.to_vec()).expect("fail to-string");
-        self.datas.insert(ino, x);
+        let new_len = self.write_range(ino, offset, data);
         if let Some(f) = self.inodes.get_mut(&ino) {
-            f.attr.size = length as u64;
+            f.attr.size = std::cmp::max(f.attr.size, new_len);
+            f.attr.blocks = blocks_for_size(f.attr.size);
+            if _req.uid() != f.attr.uid {
+                f.attr.perm &= !(S_ISUID | S_ISGID);
+            }
             f.generation += 1;
         }
         reply.written(length as u32);
@@ -226,13 +611,27 @@ impl Filesystem for MemFS {
                 attr:
                     FileAttr {
                         kind: FileType::RegularFile,
+                        uid,
+                        gid,
+                        perm,
                         ..
                     },
                 ..
-            }) => match self.datas.get(&ino) {
-                Some(x) => reply.data(x.as_bytes()),
-                None => reply.data(&[]),
-            },
+            }) => {
+                if !check_access(uid, gid, perm, _req.uid(), _req.gid(), libc::R_OK) {
+                    reply.error(EACCES);
+                    return;
+                }
+                let content = self.file_content(ino);
+                let offset = _offset as usize;
+                let len = content.len();
+                if offset >= len {
+                    reply.data(&[]);
+                    return;
+                }
+                let end = std::cmp::min(offset + _size as usize, len);
+                reply.data(&content[offset..end]);
+            }
             _ => {
                 reply.error(EACCES);
                 return;
@@ -240,6 +639,26 @@ impl Filesystem for MemFS {
         }
     }
 
+    fn access(&mut self, _req: &Request, ino: u64, mask: u32, reply: fuse::ReplyEmpty) {
+        match self.inodes.get(&ino) {
+            Some(f) => {
+                if check_access(
+                    f.attr.uid,
+                    f.attr.gid,
+                    f.attr.perm,
+                    _req.uid(),
+                    _req.gid(),
+                    mask as i32,
+                ) {
+                    reply.ok();
+                } else {
+                    reply.error(EACCES);
+                }
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
     fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: fuse::ReplyEmpty) {
         let mut ok_reply = false;
         let mut delete_ino: u64 = 0;
@@ -260,8 +679,11 @@ impl Filesystem for MemFS {
             }
         }
         if delete_ino != 0 {
-            self.inodes.remove(&delete_ino);
-            self.datas.remove(&delete_ino);
+            if let Some(f) = self.inodes.remove(&delete_ino) {
+                for chunk_ref in f.chunks {
+                    self.release_chunk(chunk_ref.hash);
+                }
+            }
         }
 
         if ok_reply {
@@ -283,7 +705,7 @@ impl Filesystem for MemFS {
         _link: &std::path::Path,
         reply: ReplyEntry,
     ) {
-        let inode = time::now().to_timespec().sec as u64;
+        let inode = self.alloc_inode();
         let f = new_file_attr(inode, 0, FileType::Symlink, _req.uid(), _req.gid());
         self.inodes.insert(
             inode,
@@ -294,10 +716,12 @@ impl Filesystem for MemFS {
                 }],
                 attr: f,
                 generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
             },
         );
-        let x = String::from_str(_link.to_str().unwrap()).expect("fail to-string");
-        self.datas.insert(inode, x);
+        let x = _link.to_str().unwrap().as_bytes().to_vec();
+        self.store_content(inode, &x);
         reply.entry(&TTL, &f, 0);
     }
 
@@ -310,13 +734,7 @@ impl Filesystem for MemFS {
                         ..
                     },
                 ..
-            }) => match self.datas.get(&_ino) {
-                Some(x) => reply.data(x.as_bytes()),
-                None => {
-                    reply.error(EACCES);
-                    return;
-                }
-            },
+            }) => reply.data(&self.file_content(_ino)),
             _ => {
                 reply.error(EACCES);
                 return;
@@ -353,32 +771,255 @@ impl Filesystem for MemFS {
             None => reply.error(ENOENT),
         }
     }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: fuse::ReplyEmpty,
+    ) {
+        match self.inodes.get_mut(&ino) {
+            Some(f) => {
+                f.xattrs
+                    .insert(name.to_str().unwrap().to_string(), value.to_vec());
+                reply.ok();
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.inodes.get(&ino) {
+            Some(f) => match f.xattrs.get(name.to_str().unwrap()) {
+                Some(value) => {
+                    if size == 0 {
+                        reply.size(value.len() as u32);
+                    } else if value.len() as u32 > size {
+                        reply.error(ERANGE);
+                    } else {
+                        reply.data(value);
+                    }
+                }
+                None => reply.error(ENODATA),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.inodes.get(&ino) {
+            Some(f) => {
+                let mut names = Vec::new();
+                for key in f.xattrs.keys() {
+                    names.extend_from_slice(key.as_bytes());
+                    names.push(0);
+                }
+                if size == 0 {
+                    reply.size(names.len() as u32);
+                } else if names.len() as u32 > size {
+                    reply.error(ERANGE);
+                } else {
+                    reply.data(&names);
+                }
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: fuse::ReplyEmpty) {
+        match self.inodes.get_mut(&ino) {
+            Some(f) => {
+                if f.xattrs.remove(name.to_str().unwrap()).is_some() {
+                    reply.ok();
+                } else {
+                    reply.error(ENODATA);
+                }
+            }
+            None => reply.error(ENOENT),
+        }
+    }
 }
 
 fn main() {
     env_logger::init();
     let mountpoint = env::args_os().nth(1).expect("usage: backlogfs MOUNTPOINT");
-    let mut inodes = HashMap::new();
-    let datas = HashMap::new();
-    // i-node numberの1はroot node, 0はbad block
-    inodes.insert(
-        1,
-        File {
-            hard_links: vec![HardLink {
-                parent_ino: 0,
-                name: "/".to_string(),
-            }],
-            attr: new_file_attr(1, 0, FileType::Directory, 501, 20),
-            generation: 0,
-        },
-    );
+    let snapshot_path = env::args_os()
+        .nth(2)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("kahirofs.tree.zst"));
+    let capacity_bytes = env::args()
+        .nth(3)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_CAPACITY_BYTES);
     fuse::mount(
-        MemFS {
-            inodes: inodes,
-            datas: datas,
-        },
+        MemFS::with_capacity(Some(snapshot_path), capacity_bytes),
         &mountpoint,
         &[],
     )
     .expect("fail mount()");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn alloc_inode_never_collides_in_a_tight_loop() {
+        let fs = MemFS::new(None);
+        let mut seen = HashSet::new();
+        for _ in 0..10_000 {
+            assert!(seen.insert(fs.alloc_inode()), "duplicate inode allocated");
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip_preserves_files_dirs_and_symlinks() {
+        let snapshot_path = std::env::temp_dir().join(format!(
+            "kahirofs-snapshot-round-trip-{}.tree.zst",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&snapshot_path);
+
+        let mut fs = MemFS::new(Some(snapshot_path.clone()));
+
+        fs.inodes.insert(
+            2,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 1,
+                    name: "hello.txt".to_string(),
+                }],
+                attr: new_file_attr(2, 0, FileType::RegularFile, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+        fs.store_content(2, b"hello world");
+
+        fs.inodes.insert(
+            3,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 1,
+                    name: "subdir".to_string(),
+                }],
+                attr: new_file_attr(3, 0, FileType::Directory, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+
+        fs.inodes.insert(
+            4,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 3,
+                    name: "link".to_string(),
+                }],
+                attr: new_file_attr(4, 0, FileType::Symlink, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+        fs.store_content(4, b"../hello.txt");
+
+        fs.save_snapshot();
+
+        let mut reloaded = MemFS::new(Some(snapshot_path.clone()));
+        reloaded.load_snapshot();
+        let _ = std::fs::remove_file(&snapshot_path);
+
+        assert_eq!(reloaded.inodes.len(), fs.inodes.len());
+        for (ino, f) in fs.inodes.iter() {
+            let r = reloaded.inodes.get(ino).expect("inode missing after reload");
+            assert_eq!(r.attr.kind, f.attr.kind);
+            assert_eq!(r.hard_links[0].parent_ino, f.hard_links[0].parent_ino);
+            assert_eq!(r.hard_links[0].name, f.hard_links[0].name);
+        }
+        assert_eq!(reloaded.file_content(2), fs.file_content(2));
+        assert_eq!(reloaded.file_content(4), fs.file_content(4));
+    }
+
+    #[test]
+    fn store_content_deduplicates_identical_chunks() {
+        let mut fs = MemFS::new(None);
+        let content = vec![7u8; chunking::MAX_CHUNK_SIZE * 3];
+
+        fs.inodes.insert(
+            2,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 1,
+                    name: "a".to_string(),
+                }],
+                attr: new_file_attr(2, 0, FileType::RegularFile, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+        fs.inodes.insert(
+            3,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 1,
+                    name: "b".to_string(),
+                }],
+                attr: new_file_attr(3, 0, FileType::RegularFile, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+
+        fs.store_content(2, &content);
+        fs.store_content(3, &content);
+
+        assert_eq!(fs.file_content(2), content);
+        assert_eq!(fs.file_content(3), content);
+        let distinct: HashSet<ChunkHash> = fs.inodes[&2].chunks.iter().map(|c| c.hash).collect();
+        assert_eq!(fs.chunks.len(), distinct.len());
+
+        fs.store_content(3, b"");
+        assert!(!fs.chunks.is_empty());
+        fs.store_content(2, b"");
+        assert!(fs.chunks.is_empty());
+        assert!(fs.chunk_refs.is_empty());
+    }
+
+    #[test]
+    fn truncate_then_write_does_not_resurrect_stale_tail_bytes() {
+        let mut fs = MemFS::new(None);
+        fs.inodes.insert(
+            2,
+            File {
+                hard_links: vec![HardLink {
+                    parent_ino: 1,
+                    name: "a".to_string(),
+                }],
+                attr: new_file_attr(2, 0, FileType::RegularFile, 501, 20),
+                generation: 0,
+                chunks: vec![],
+                xattrs: HashMap::new(),
+            },
+        );
+        fs.store_content(2, b"hello world, this is the old content");
+
+        // O_TRUNC-on-open is implemented as setattr(size=0) followed by a
+        // write of the new, shorter data.
+        let truncated_len = fs.truncate_content(2, 0);
+        assert_eq!(truncated_len, 0);
+        let written_len = fs.write_range(2, 0, b"new\n");
+
+        assert_eq!(written_len, 4);
+        assert_eq!(fs.file_content(2), b"new\n");
+    }
+}
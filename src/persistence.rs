@@ -0,0 +1,174 @@
+use super::{ChunkHash, ChunkRef, File, HardLink, INode};
+use fuse::{FileAttr, FileType};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use time::Timespec;
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FileType")]
+enum FileTypeDef {
+    NamedPipe,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    RegularFile,
+    Symlink,
+    Socket,
+}
+
+// time::Timespec doesn't implement serde's traits itself (only the
+// optional rustc-serialize feature), so it needs its own remote shim too.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Timespec")]
+struct TimespecDef {
+    sec: i64,
+    nsec: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FileAttr")]
+struct FileAttrDef {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    #[serde(with = "TimespecDef")]
+    atime: Timespec,
+    #[serde(with = "TimespecDef")]
+    mtime: Timespec,
+    #[serde(with = "TimespecDef")]
+    ctime: Timespec,
+    #[serde(with = "TimespecDef")]
+    crtime: Timespec,
+    #[serde(with = "FileTypeDef")]
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    flags: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedHardLink {
+    parent_ino: INode,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedChunkRef {
+    hash: ChunkHash,
+    len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedFile {
+    hard_links: Vec<PersistedHardLink>,
+    #[serde(with = "FileAttrDef")]
+    attr: FileAttr,
+    generation: u64,
+    chunks: Vec<PersistedChunkRef>,
+    xattrs: HashMap<String, Vec<u8>>,
+}
+
+// On-disk layout for a full tree snapshot: the hard-link/attr index plus the
+// deduplicated chunk store and its reference counts, bincode-encoded and
+// then zstd-compressed.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    inodes: HashMap<INode, PersistedFile>,
+    chunks: HashMap<ChunkHash, Vec<u8>>,
+    chunk_refs: HashMap<ChunkHash, usize>,
+}
+
+type RestoredState = (
+    HashMap<INode, File>,
+    HashMap<ChunkHash, Vec<u8>>,
+    HashMap<ChunkHash, usize>,
+);
+
+impl Snapshot {
+    pub fn capture(
+        inodes: &HashMap<INode, File>,
+        chunks: &HashMap<ChunkHash, Vec<u8>>,
+        chunk_refs: &HashMap<ChunkHash, usize>,
+    ) -> Snapshot {
+        let persisted_inodes = inodes
+            .iter()
+            .map(|(&ino, f)| {
+                let persisted = PersistedFile {
+                    hard_links: f
+                        .hard_links
+                        .iter()
+                        .map(|h| PersistedHardLink {
+                            parent_ino: h.parent_ino,
+                            name: h.name.clone(),
+                        })
+                        .collect(),
+                    attr: f.attr,
+                    generation: f.generation,
+                    chunks: f
+                        .chunks
+                        .iter()
+                        .map(|c| PersistedChunkRef {
+                            hash: c.hash,
+                            len: c.len,
+                        })
+                        .collect(),
+                    xattrs: f.xattrs.clone(),
+                };
+                (ino, persisted)
+            })
+            .collect();
+        Snapshot {
+            inodes: persisted_inodes,
+            chunks: chunks.clone(),
+            chunk_refs: chunk_refs.clone(),
+        }
+    }
+
+    pub fn restore(self) -> RestoredState {
+        let inodes = self
+            .inodes
+            .into_iter()
+            .map(|(ino, f)| {
+                let file = File {
+                    hard_links: f
+                        .hard_links
+                        .into_iter()
+                        .map(|h| HardLink {
+                            parent_ino: h.parent_ino,
+                            name: h.name,
+                        })
+                        .collect(),
+                    attr: f.attr,
+                    generation: f.generation,
+                    chunks: f
+                        .chunks
+                        .into_iter()
+                        .map(|c| ChunkRef {
+                            hash: c.hash,
+                            len: c.len,
+                        })
+                        .collect(),
+                    xattrs: f.xattrs,
+                };
+                (ino, file)
+            })
+            .collect();
+        (inodes, self.chunks, self.chunk_refs)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let encoded = bincode::serialize(self).expect("fail to serialize snapshot");
+        let compressed = zstd::encode_all(encoded.as_slice(), 0)?;
+        std::fs::write(path, compressed)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Snapshot> {
+        let compressed = std::fs::read(path)?;
+        let encoded = zstd::decode_all(compressed.as_slice())?;
+        Ok(bincode::deserialize(&encoded).expect("fail to deserialize snapshot"))
+    }
+}